@@ -22,8 +22,14 @@
 
 use crate::util::SipHasherBuild;
 
+use aead::{Aead, NewAead};
+use blake2::Digest as _;
 use futures::lock::Mutex;
 use rand::{Rng as _, SeedableRng as _};
+use schnorrkel::derive::Derivation as _;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path};
+use xsalsa20poly1305::XSalsa20Poly1305;
 
 /// Namespace of the key.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -50,8 +56,9 @@ impl KeyNamespace {
         .into_iter()
     }
 
-    // TODO: use or remove
-    /*fn as_string(&self) -> &'static [u8; 4] {
+    /// Returns the 4-character ASCII identifier used by Substrate to refer to this namespace,
+    /// and that this module reuses as a prefix for on-disk key file names.
+    fn as_bytes(&self) -> &'static [u8; 4] {
         match self {
             KeyNamespace::Aura => b"aura",
             KeyNamespace::AuthorityDiscovery => b"audi",
@@ -59,7 +66,48 @@ impl KeyNamespace {
             KeyNamespace::Grandpa => b"gran",
             KeyNamespace::ImOnline => b"imon",
         }
-    }*/
+    }
+
+    /// Inverse of [`KeyNamespace::as_bytes`].
+    fn from_bytes(bytes: &[u8; 4]) -> Option<Self> {
+        Some(match bytes {
+            b"aura" => KeyNamespace::Aura,
+            b"audi" => KeyNamespace::AuthorityDiscovery,
+            b"babe" => KeyNamespace::Babe,
+            b"gran" => KeyNamespace::Grandpa,
+            b"imon" => KeyNamespace::ImOnline,
+            _ => return None,
+        })
+    }
+}
+
+/// Public key known to a [`Keystore`].
+///
+/// Unlike Ed25519 and Sr25519, whose public keys are both 32 bytes, Ecdsa public keys are 33
+/// bytes (compressed form), which is why this is an enum rather than a plain `[u8; 32]`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PublicKey {
+    Ed25519([u8; 32]),
+    Sr25519([u8; 32]),
+    Ecdsa([u8; 33]),
+}
+
+impl PublicKey {
+    fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            PublicKey::Ed25519(_) => KeyAlgorithm::Ed25519,
+            PublicKey::Sr25519(_) => KeyAlgorithm::Sr25519,
+            PublicKey::Ecdsa(_) => KeyAlgorithm::Ecdsa,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            PublicKey::Ed25519(bytes) => &bytes[..],
+            PublicKey::Sr25519(bytes) => &bytes[..],
+            PublicKey::Ecdsa(bytes) => &bytes[..],
+        }
+    }
 }
 
 /// Collection of key pairs.
@@ -67,24 +115,72 @@ impl KeyNamespace {
 /// This module doesn't give you access to the content of private keys, only to signing
 /// capabilities.
 pub struct Keystore {
+    /// Directory on disk that generated/inserted keys are saved to, if any. `None` if this
+    /// keystore was built with [`Keystore::new`] rather than [`Keystore::with_directory`].
+    directory: Option<path::PathBuf>,
     guarded: Mutex<Guarded>,
 }
 
 impl Keystore {
-    /// Initializes a new keystore.
+    /// Initializes a new keystore that only ever keeps keys in memory.
     ///
     /// Must be passed bytes of entropy that are used to avoid hash collision attacks and to
     /// generate private keys.
     pub fn new(randomness_seed: [u8; 32]) -> Self {
-        let mut gen_rng = rand_chacha::ChaCha20Rng::from_seed(randomness_seed);
+        Keystore {
+            directory: None,
+            guarded: Mutex::new(Guarded::empty(randomness_seed)),
+        }
+    }
 
-        let keys = hashbrown::HashMap::with_capacity_and_hasher(32, {
-            SipHasherBuild::new(gen_rng.sample(rand::distributions::Standard))
-        });
+    /// Initializes a new keystore that loads its content from, and persists generated/inserted
+    /// keys to, the given directory.
+    ///
+    /// Every file directly inside `directory` is assumed to be a key file previously written by
+    /// this same module, named `hex(namespace || public_key)` and containing a blob encrypted
+    /// using `passphrase`. Files that don't match this scheme are silently ignored, so that the
+    /// directory can also be used by the embedder for other purposes.
+    ///
+    /// Must be passed bytes of entropy that are used to avoid hash collision attacks and to
+    /// generate private keys.
+    ///
+    /// Returns an error if the directory couldn't be read, or if a key file within it couldn't
+    /// be decrypted, for example because `passphrase` is wrong.
+    pub fn with_directory(
+        randomness_seed: [u8; 32],
+        directory: impl Into<path::PathBuf>,
+        passphrase: &str,
+    ) -> Result<Self, KeystoreLoadError> {
+        let directory = directory.into();
+        let mut guarded = Guarded::empty(randomness_seed);
 
-        Keystore {
-            guarded: Mutex::new(Guarded { gen_rng, keys }),
+        for entry in fs::read_dir(&directory).map_err(KeystoreLoadError::Io)? {
+            let entry = entry.map_err(KeystoreLoadError::Io)?;
+            if !entry.file_type().map_err(KeystoreLoadError::Io)?.is_file() {
+                continue;
+            }
+
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+
+            let (namespace, public_key_bytes) = match decode_file_name(file_name) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+
+            let contents = fs::read(entry.path()).map_err(KeystoreLoadError::Io)?;
+            let (public_key, private_key) =
+                decrypt_key_file(&contents, &public_key_bytes, passphrase)?;
+            guarded.keys.insert((namespace, public_key), private_key);
         }
+
+        Ok(Keystore {
+            directory: Some(directory),
+            guarded: Mutex::new(guarded),
+        })
     }
 
     /// Inserts an Sr25519 private key in the keystore.
@@ -94,6 +190,10 @@ impl Keystore {
     /// This is meant to be called with publicly-known private keys. Use
     /// [`Keystore::generate_sr25519`] if the private key is meant to actually be private.
     ///
+    /// If `save` contains a passphrase, the key is additionally written to disk, encrypted with
+    /// that passphrase. This requires the keystore to have been created with
+    /// [`Keystore::with_directory`].
+    ///
     /// # Panic
     ///
     /// Panics if the key isn't a valid Sr25519 private key. This function is meant to be used
@@ -104,26 +204,108 @@ impl Keystore {
         &mut self,
         namespaces: impl Iterator<Item = KeyNamespace>,
         private_key: &[u8; 64],
-    ) -> [u8; 32] {
+        save: Option<&str>,
+    ) -> Result<[u8; 32], KeystoreSaveError> {
         let private_key = schnorrkel::SecretKey::from_bytes(&private_key[..]).unwrap();
         let keypair = private_key.to_keypair();
         let public_key = keypair.public.to_bytes();
 
+        let namespaces = namespaces.collect::<Vec<_>>();
+
+        if let Some(passphrase) = save {
+            let directory = self
+                .directory
+                .as_ref()
+                .ok_or(KeystoreSaveError::NoDirectory)?;
+            for namespace in namespaces.iter().copied() {
+                save_key_file(
+                    directory,
+                    namespace,
+                    &PublicKey::Sr25519(public_key),
+                    &keypair.secret.to_bytes(),
+                    passphrase,
+                    &mut self.guarded.get_mut().gen_rng,
+                )?;
+            }
+        }
+
         for namespace in namespaces {
             self.guarded.get_mut().keys.insert(
-                (namespace, public_key),
+                (namespace, PublicKey::Sr25519(public_key)),
                 PrivateKey::MemorySr25519(keypair.clone()),
             );
         }
 
-        public_key
+        Ok(public_key)
+    }
+
+    /// Inserts an Ecdsa private key in the keystore.
+    ///
+    /// Returns the corresponding 33-byte compressed public key.
+    ///
+    /// This is meant to be called with publicly-known private keys. Use
+    /// [`Keystore::generate_ecdsa`] if the private key is meant to actually be private.
+    ///
+    /// If `save` contains a passphrase, the key is additionally written to disk, encrypted with
+    /// that passphrase. This requires the keystore to have been created with
+    /// [`Keystore::with_directory`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the key isn't a valid Ecdsa private key. This function is meant to be used
+    /// with hard coded values which are known to be correct. Please do not call it with any
+    /// sort of user input.
+    ///
+    pub fn insert_ecdsa_memory(
+        &mut self,
+        namespaces: impl Iterator<Item = KeyNamespace>,
+        private_key: &[u8; 32],
+        save: Option<&str>,
+    ) -> Result<[u8; 33], KeystoreSaveError> {
+        let signing_key = k256::ecdsa::SigningKey::from_bytes(&private_key[..]).unwrap();
+        let public_key = compressed_ecdsa_public_key(&signing_key);
+
+        let namespaces = namespaces.collect::<Vec<_>>();
+
+        if let Some(passphrase) = save {
+            let directory = self
+                .directory
+                .as_ref()
+                .ok_or(KeystoreSaveError::NoDirectory)?;
+            for namespace in namespaces.iter().copied() {
+                save_key_file(
+                    directory,
+                    namespace,
+                    &PublicKey::Ecdsa(public_key),
+                    private_key,
+                    passphrase,
+                    &mut self.guarded.get_mut().gen_rng,
+                )?;
+            }
+        }
+
+        for namespace in namespaces {
+            self.guarded.get_mut().keys.insert(
+                (namespace, PublicKey::Ecdsa(public_key)),
+                PrivateKey::MemoryEcdsa(signing_key.clone()),
+            );
+        }
+
+        Ok(public_key)
     }
 
     /// Generates a new Ed25519 key and inserts it in the keystore.
     ///
     /// Returns the corresponding public key.
-    // TODO: add a `save: bool` parameter that saves the key to the file system
-    pub async fn generate_ed25519(&self, namespace: KeyNamespace) -> [u8; 32] {
+    ///
+    /// If `save` contains a passphrase, the key is additionally written to disk, encrypted with
+    /// that passphrase. This requires the keystore to have been created with
+    /// [`Keystore::with_directory`].
+    pub async fn generate_ed25519(
+        &self,
+        namespace: KeyNamespace,
+        save: Option<&str>,
+    ) -> Result<[u8; 32], KeystoreSaveError> {
         let mut guarded = self.guarded.lock().await;
 
         // Note: it is in principle possible to generate some entropy from the PRNG, then unlock
@@ -132,28 +314,43 @@ impl Keystore {
         // is not worth the effort.
         let private_key = ed25519_zebra::SigningKey::new(&mut guarded.gen_rng);
         let public_key = ed25519_zebra::VerificationKey::from(&private_key);
+        let public_key_bytes: [u8; 32] = public_key.into();
+
+        if let Some(passphrase) = save {
+            let directory = self
+                .directory
+                .as_ref()
+                .ok_or(KeystoreSaveError::NoDirectory)?;
+            save_key_file(
+                directory,
+                namespace,
+                &PublicKey::Ed25519(public_key_bytes),
+                private_key.as_ref(),
+                passphrase,
+                &mut guarded.gen_rng,
+            )?;
+        }
+
         guarded.keys.insert(
-            (namespace, public_key.into()),
+            (namespace, PublicKey::Ed25519(public_key_bytes)),
             PrivateKey::MemoryEd25519(private_key),
         );
 
-        public_key.into()
-    }
-
-    /// Returns the list of all keys known to this keystore.
-    ///
-    /// > **Note**: Keep in mind that this function is racy, as keys can be added and removed
-    /// >           in parallel.
-    pub async fn keys(&self) -> impl Iterator<Item = (KeyNamespace, [u8; 32])> {
-        let guarded = self.guarded.lock().await;
-        guarded.keys.keys().cloned().collect::<Vec<_>>().into_iter()
+        Ok(public_key_bytes)
     }
 
     /// Generates a new Sr25519 key and inserts it in the keystore.
     ///
     /// Returns the corresponding public key.
-    // TODO: add a `save: bool` parameter that saves the key to the file system
-    pub async fn generate_sr25519(&self, namespace: KeyNamespace) -> [u8; 32] {
+    ///
+    /// If `save` contains a passphrase, the key is additionally written to disk, encrypted with
+    /// that passphrase. This requires the keystore to have been created with
+    /// [`Keystore::with_directory`].
+    pub async fn generate_sr25519(
+        &self,
+        namespace: KeyNamespace,
+        save: Option<&str>,
+    ) -> Result<[u8; 32], KeystoreSaveError> {
         let mut guarded = self.guarded.lock().await;
 
         // Note: it is in principle possible to generate some entropy from the PRNG, then unlock
@@ -162,21 +359,189 @@ impl Keystore {
         // is not worth the effort.
         let keypair = schnorrkel::Keypair::generate_with(&mut guarded.gen_rng);
         let public_key = keypair.public.to_bytes();
-        guarded
+
+        if let Some(passphrase) = save {
+            let directory = self
+                .directory
+                .as_ref()
+                .ok_or(KeystoreSaveError::NoDirectory)?;
+            save_key_file(
+                directory,
+                namespace,
+                &PublicKey::Sr25519(public_key),
+                &keypair.secret.to_bytes(),
+                passphrase,
+                &mut guarded.gen_rng,
+            )?;
+        }
+
+        guarded.keys.insert(
+            (namespace, PublicKey::Sr25519(public_key)),
+            PrivateKey::MemorySr25519(keypair),
+        );
+
+        Ok(public_key)
+    }
+
+    /// Generates a new Ecdsa key and inserts it in the keystore.
+    ///
+    /// Returns the corresponding 33-byte compressed public key.
+    ///
+    /// If `save` contains a passphrase, the key is additionally written to disk, encrypted with
+    /// that passphrase. This requires the keystore to have been created with
+    /// [`Keystore::with_directory`].
+    pub async fn generate_ecdsa(
+        &self,
+        namespace: KeyNamespace,
+        save: Option<&str>,
+    ) -> Result<[u8; 33], KeystoreSaveError> {
+        let mut guarded = self.guarded.lock().await;
+
+        let mut private_key_bytes = [0u8; 32];
+        guarded.gen_rng.fill(&mut private_key_bytes);
+        let signing_key = k256::ecdsa::SigningKey::from_bytes(&private_key_bytes[..])
+            .expect("rejection sampling on a 32-byte secp256k1 scalar practically never fails");
+        let public_key = compressed_ecdsa_public_key(&signing_key);
+
+        if let Some(passphrase) = save {
+            let directory = self
+                .directory
+                .as_ref()
+                .ok_or(KeystoreSaveError::NoDirectory)?;
+            save_key_file(
+                directory,
+                namespace,
+                &PublicKey::Ecdsa(public_key),
+                &private_key_bytes,
+                passphrase,
+                &mut guarded.gen_rng,
+            )?;
+        }
+
+        guarded.keys.insert(
+            (namespace, PublicKey::Ecdsa(public_key)),
+            PrivateKey::MemoryEcdsa(signing_key),
+        );
+
+        Ok(public_key)
+    }
+
+    /// Derives a child key from an already-present key, following the given SURI-style junction
+    /// path, and inserts it in the keystore under `namespace`.
+    ///
+    /// Returns the public key of the derived key. This lets callers reproduce Polkadot's account
+    /// derivation (e.g. `//Alice`, stash/controller patterns) entirely within the keystore
+    /// without ever exporting the parent's secret.
+    ///
+    /// Sr25519 keys support both [`DeriveJunction::Hard`] and [`DeriveJunction::Soft`] junctions.
+    /// Ed25519 keys only support hard junctions; deriving through a soft junction returns
+    /// [`DeriveError::SoftJunctionUnsupported`]. Ecdsa keys don't support derivation at all.
+    ///
+    /// If `save` contains a passphrase, the derived key is additionally written to disk,
+    /// encrypted with that passphrase. This requires the keystore to have been created with
+    /// [`Keystore::with_directory`].
+    pub async fn derive(
+        &self,
+        namespace: KeyNamespace,
+        base_public_key: &PublicKey,
+        path: impl Iterator<Item = DeriveJunction>,
+        save: Option<&str>,
+    ) -> Result<PublicKey, DeriveError> {
+        let mut guarded = self.guarded.lock().await;
+        let base_key = guarded
             .keys
-            .insert((namespace, public_key), PrivateKey::MemorySr25519(keypair));
+            .get(&(namespace, *base_public_key))
+            .ok_or(DeriveError::Sign(SignError::UnknownPublicKey))?;
 
-        public_key
+        let (public_key, private_key, private_key_bytes) = match base_key {
+            PrivateKey::MemoryEd25519(key) => {
+                let mut seed: [u8; 32] = key.as_ref().try_into().unwrap_or_else(|_| unreachable!());
+                for junction in path {
+                    let chain_code = match junction {
+                        DeriveJunction::Hard(chain_code) => chain_code,
+                        DeriveJunction::Soft(_) => {
+                            return Err(DeriveError::SoftJunctionUnsupported)
+                        }
+                    };
+                    seed = derive_ed25519_hard(&seed, &chain_code);
+                }
+
+                let signing_key =
+                    ed25519_zebra::SigningKey::try_from(seed).unwrap_or_else(|_| unreachable!());
+                let public_key: [u8; 32] =
+                    ed25519_zebra::VerificationKey::from(&signing_key).into();
+                (
+                    PublicKey::Ed25519(public_key),
+                    PrivateKey::MemoryEd25519(signing_key),
+                    seed.to_vec(),
+                )
+            }
+            PrivateKey::MemorySr25519(keypair) => {
+                let mut secret = keypair.secret.clone();
+                for junction in path {
+                    secret = match junction {
+                        DeriveJunction::Hard(chain_code) => {
+                            derive_sr25519_hard(&secret, &chain_code)
+                        }
+                        DeriveJunction::Soft(chain_code) => {
+                            derive_sr25519_soft(&secret, &chain_code)
+                        }
+                    };
+                }
+
+                let keypair = secret.to_keypair();
+                let public_key = keypair.public.to_bytes();
+                let private_key_bytes = keypair.secret.to_bytes().to_vec();
+                (
+                    PublicKey::Sr25519(public_key),
+                    PrivateKey::MemorySr25519(keypair),
+                    private_key_bytes,
+                )
+            }
+            PrivateKey::MemoryEcdsa(_) => return Err(DeriveError::UnsupportedAlgorithm),
+        };
+
+        if let Some(passphrase) = save {
+            let directory = self
+                .directory
+                .as_ref()
+                .ok_or(DeriveError::Save(KeystoreSaveError::NoDirectory))?;
+            save_key_file(
+                directory,
+                namespace,
+                &public_key,
+                &private_key_bytes,
+                passphrase,
+                &mut guarded.gen_rng,
+            )
+            .map_err(DeriveError::Save)?;
+        }
+
+        guarded.keys.insert((namespace, public_key), private_key);
+        Ok(public_key)
+    }
+
+    /// Returns the list of all keys known to this keystore.
+    ///
+    /// > **Note**: Keep in mind that this function is racy, as keys can be added and removed
+    /// >           in parallel.
+    pub async fn keys(&self) -> impl Iterator<Item = (KeyNamespace, PublicKey)> {
+        let guarded = self.guarded.lock().await;
+        guarded.keys.keys().cloned().collect::<Vec<_>>().into_iter()
     }
 
     /// Signs the given payload using the private key associated to the public key passed as
     /// parameter.
+    ///
+    /// For Ecdsa keys, the payload is first hashed with Blake2-256, and the resulting 65-byte
+    /// signature is the 64-byte recoverable ECDSA signature followed by the recovery
+    /// identifier, matching Substrate's `ecdsa::Pair::sign` convention.
     pub async fn sign(
         &self,
         key_namespace: KeyNamespace,
-        public_key: &[u8; 32],
+        public_key: &PublicKey,
         payload: &[u8],
-    ) -> Result<[u8; 64], SignError> {
+    ) -> Result<Signature, SignError> {
         let guarded = self.guarded.lock().await;
         let key = guarded
             .keys
@@ -184,11 +549,21 @@ impl Keystore {
             .ok_or(SignError::UnknownPublicKey)?;
 
         match key {
-            PrivateKey::MemoryEd25519(key) => Ok(key.sign(payload).into()),
+            PrivateKey::MemoryEd25519(key) => Ok(Signature::Ed25519(key.sign(payload).into())),
             PrivateKey::MemorySr25519(key) => {
                 // TODO: is creating the signing context expensive?
                 let context = schnorrkel::signing_context(b"substrate");
-                Ok(key.sign(context.bytes(payload)).to_bytes())
+                Ok(Signature::Sr25519(key.sign(context.bytes(payload)).to_bytes()))
+            }
+            PrivateKey::MemoryEcdsa(key) => {
+                let hashed_payload = Blake2b256::digest(payload);
+                let (signature, recovery_id) = key
+                    .sign_prehash_recoverable(&hashed_payload)
+                    .map_err(|_| SignError::UnknownPublicKey)?;
+                let mut out = [0; 65];
+                out[..64].copy_from_slice(&signature.to_bytes());
+                out[64] = recovery_id.to_byte();
+                Ok(Signature::Ecdsa(out))
             }
         }
     }
@@ -208,11 +583,13 @@ impl Keystore {
             let guarded = self.guarded.lock().await;
             let key = guarded
                 .keys
-                .get(&(key_namespace, *public_key))
+                .get(&(key_namespace, PublicKey::Sr25519(*public_key)))
                 .ok_or(SignVrfError::Sign(SignError::UnknownPublicKey))?;
 
             match key {
-                PrivateKey::MemoryEd25519(_) => Err(SignVrfError::WrongKeyAlgorithm),
+                PrivateKey::MemoryEd25519(_) | PrivateKey::MemoryEcdsa(_) => {
+                    Err(SignVrfError::WrongKeyAlgorithm)
+                }
                 PrivateKey::MemorySr25519(key) => {
                     let mut transcript = merlin::Transcript::new(label);
                     for (label, value) in transcript_items {
@@ -226,26 +603,473 @@ impl Keystore {
                         }
                     }
 
-                    let (_in_out, proof, _) = key.vrf_sign(transcript);
+                    let (in_out, proof, _) = key.vrf_sign(transcript);
                     Ok(VrfSignature {
-                        // TODO: should probably output the `_in_out` as well
+                        output: in_out.to_output().to_bytes(),
                         proof: proof.to_bytes(),
                     })
                 }
             }
         }
     }
+
+    /// Verifies a VRF proof and output previously produced by [`Keystore::sign_sr25519_vrf`] for
+    /// the given public key, label, and transcript.
+    ///
+    /// This doesn't require access to a [`Keystore`] instance, as verification only needs the
+    /// public key, which is why this is an associated function rather than a method.
+    ///
+    /// Note that the labels must be `'static` due to requirements from the underlying library.
+    pub fn verify_sr25519_vrf<'a>(
+        public_key: &'a [u8; 32],
+        label: &'static [u8],
+        transcript_items: impl Iterator<Item = (&'static [u8], either::Either<&'a [u8], u64>)>,
+        output: &[u8; 32],
+        proof: &[u8; 64],
+    ) -> Result<(), VerifyVrfError> {
+        let public_key = schnorrkel::PublicKey::from_bytes(&public_key[..])
+            .map_err(|_| VerifyVrfError::InvalidPublicKey)?;
+
+        let mut transcript = merlin::Transcript::new(label);
+        for (label, value) in transcript_items {
+            match value {
+                either::Left(bytes) => {
+                    transcript.append_message(label, bytes);
+                }
+                either::Right(value) => {
+                    transcript.append_u64(label, value);
+                }
+            }
+        }
+
+        let output = schnorrkel::vrf::VRFPreOut::from_bytes(output)
+            .map_err(|_| VerifyVrfError::InvalidOutput)?;
+        let proof = schnorrkel::vrf::VRFProof::from_bytes(proof)
+            .map_err(|_| VerifyVrfError::InvalidProof)?;
+
+        public_key
+            .vrf_verify(transcript, &output, &proof)
+            .map_err(|_| VerifyVrfError::VerificationFailed)?;
+
+        Ok(())
+    }
+
+    /// Exports a key as an encrypted JSON document compatible with the format used by
+    /// Substrate's keystore and the Polkadot.js extension, so that it can be imported into
+    /// other tooling.
+    ///
+    /// Only Ed25519 and Sr25519 keys are supported, as this JSON format has no Ecdsa variant in
+    /// the wider ecosystem.
+    pub async fn export_json(
+        &self,
+        namespace: KeyNamespace,
+        public_key: &PublicKey,
+        passphrase: &str,
+    ) -> Result<String, ExportJsonError> {
+        let mut guarded = self.guarded.lock().await;
+
+        let (algorithm, pkcs8) = {
+            let key = guarded
+                .keys
+                .get(&(namespace, *public_key))
+                .ok_or(ExportJsonError::Sign(SignError::UnknownPublicKey))?;
+
+            match key {
+                PrivateKey::MemoryEd25519(key) => {
+                    let seed: [u8; 32] =
+                        key.as_ref().try_into().unwrap_or_else(|_| unreachable!());
+                    let public: [u8; 32] = match public_key {
+                        PublicKey::Ed25519(public) => *public,
+                        _ => unreachable!(),
+                    };
+                    ("ed25519", pkcs8_encode_ed25519(&seed, &public))
+                }
+                PrivateKey::MemorySr25519(key) => {
+                    let public: [u8; 32] = match public_key {
+                        PublicKey::Sr25519(public) => *public,
+                        _ => unreachable!(),
+                    };
+                    let mut bytes = key.secret.to_bytes().to_vec();
+                    bytes.extend_from_slice(&public);
+                    ("sr25519", bytes)
+                }
+                PrivateKey::MemoryEcdsa(_) => {
+                    return Err(ExportJsonError::UnsupportedAlgorithm)
+                }
+            }
+        };
+
+        let mut salt = [0u8; SALT_LEN];
+        guarded.gen_rng.fill(&mut salt);
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        guarded.gen_rng.fill(&mut nonce_bytes);
+
+        let encryption_key = derive_encryption_key(passphrase, &salt);
+        let cipher = XSalsa20Poly1305::new(aead::generic_array::GenericArray::from_slice(
+            &encryption_key,
+        ));
+        let ciphertext = cipher
+            .encrypt(
+                aead::generic_array::GenericArray::from_slice(&nonce_bytes),
+                &pkcs8[..],
+            )
+            .unwrap_or_else(|_| unreachable!());
+
+        let mut encoded = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+        encoded.extend_from_slice(&salt);
+        encoded.extend_from_slice(&nonce_bytes);
+        encoded.extend_from_slice(&ciphertext);
+
+        let file = JsonKeyFile {
+            encoded: base64::encode(encoded),
+            encoding: JsonKeyEncoding {
+                content: ("pkcs8".to_string(), algorithm.to_string()),
+                ty: vec!["scrypt".to_string(), "xsalsa20-poly1305".to_string()],
+                version: "3".to_string(),
+            },
+            // This module doesn't implement SS58 address encoding, which is chain-specific;
+            // embedders that need a proper address can re-derive it from the public key.
+            address: format!("0x{}", hex::encode(public_key.as_bytes())),
+            meta: serde_json::json!({}),
+        };
+
+        Ok(serde_json::to_string(&file).unwrap_or_else(|_| unreachable!()))
+    }
+
+    /// Imports a key from the encrypted JSON format produced by [`Keystore::export_json`], or by
+    /// Substrate's keystore or the Polkadot.js extension, and inserts it under the given
+    /// namespaces.
+    ///
+    /// Returns the public key of the imported key.
+    pub async fn import_json(
+        &self,
+        namespaces: impl Iterator<Item = KeyNamespace>,
+        json: &str,
+        passphrase: &str,
+    ) -> Result<PublicKey, ImportJsonError> {
+        let file: JsonKeyFile =
+            serde_json::from_str(json).map_err(|_| ImportJsonError::InvalidJson)?;
+
+        if !file.encoding.ty.iter().any(|ty| ty == "scrypt")
+            || !file.encoding.ty.iter().any(|ty| ty == "xsalsa20-poly1305")
+        {
+            return Err(ImportJsonError::UnsupportedEncoding);
+        }
+        if file.encoding.content.0 != "pkcs8" {
+            return Err(ImportJsonError::UnsupportedEncoding);
+        }
+
+        let encoded = base64::decode(&file.encoded).map_err(|_| ImportJsonError::InvalidJson)?;
+        if encoded.len() < SALT_LEN + NONCE_LEN {
+            return Err(ImportJsonError::InvalidJson);
+        }
+
+        let salt: [u8; SALT_LEN] = encoded[..SALT_LEN].try_into().unwrap();
+        let nonce_bytes: [u8; NONCE_LEN] =
+            encoded[SALT_LEN..SALT_LEN + NONCE_LEN].try_into().unwrap();
+        let ciphertext = &encoded[SALT_LEN + NONCE_LEN..];
+
+        let encryption_key = derive_encryption_key(passphrase, &salt);
+        let cipher = XSalsa20Poly1305::new(aead::generic_array::GenericArray::from_slice(
+            &encryption_key,
+        ));
+        let pkcs8 = cipher
+            .decrypt(
+                aead::generic_array::GenericArray::from_slice(&nonce_bytes),
+                ciphertext,
+            )
+            .map_err(|_| ImportJsonError::Decrypt)?;
+
+        let namespaces = namespaces.collect::<Vec<_>>();
+
+        let public_key = match file.encoding.content.1.as_str() {
+            "ed25519" => {
+                let (seed, decoded_public) =
+                    pkcs8_decode_ed25519(&pkcs8).ok_or(ImportJsonError::InvalidJson)?;
+                let signing_key = ed25519_zebra::SigningKey::try_from(seed)
+                    .map_err(|_| ImportJsonError::InvalidJson)?;
+                // Derive the public key from the seed rather than trusting the document's
+                // embedded field, and reject the file if they disagree, matching the sr25519
+                // branch below.
+                let public: [u8; 32] = ed25519_zebra::VerificationKey::from(&signing_key).into();
+                if public != decoded_public {
+                    return Err(ImportJsonError::InvalidJson);
+                }
+                let public_key = PublicKey::Ed25519(public);
+
+                let mut guarded = self.guarded.lock().await;
+                for namespace in namespaces {
+                    let signing_key = ed25519_zebra::SigningKey::try_from(seed)
+                        .unwrap_or_else(|_| unreachable!());
+                    guarded
+                        .keys
+                        .insert((namespace, public_key), PrivateKey::MemoryEd25519(signing_key));
+                }
+
+                public_key
+            }
+            "sr25519" => {
+                if pkcs8.len() != 64 + 32 {
+                    return Err(ImportJsonError::InvalidJson);
+                }
+                let secret = schnorrkel::SecretKey::from_bytes(&pkcs8[..64])
+                    .map_err(|_| ImportJsonError::InvalidJson)?;
+                let keypair = secret.to_keypair();
+                let public = keypair.public.to_bytes();
+                if pkcs8[64..] != public[..] {
+                    return Err(ImportJsonError::InvalidJson);
+                }
+                let public_key = PublicKey::Sr25519(public);
+
+                let mut guarded = self.guarded.lock().await;
+                for namespace in namespaces {
+                    guarded
+                        .keys
+                        .insert((namespace, public_key), PrivateKey::MemorySr25519(keypair.clone()));
+                }
+
+                public_key
+            }
+            _ => return Err(ImportJsonError::UnsupportedAlgorithm),
+        };
+
+        Ok(public_key)
+    }
+
+    /// Returns `true` if a key with the given namespace and public key is present in the
+    /// keystore.
+    ///
+    /// This is equivalent to, but cheaper than, checking whether [`Keystore::keys`] contains the
+    /// pair, as it doesn't require cloning the full list of keys.
+    pub async fn has_key(&self, namespace: KeyNamespace, public_key: &PublicKey) -> bool {
+        let guarded = self.guarded.lock().await;
+        guarded.keys.contains_key(&(namespace, *public_key))
+    }
+
+    /// Removes a key from the keystore, including its on-disk file if the keystore was created
+    /// with [`Keystore::with_directory`] and the key had been persisted.
+    ///
+    /// Does nothing if no such key is present.
+    pub async fn remove(&self, namespace: KeyNamespace, public_key: &PublicKey) {
+        let mut guarded = self.guarded.lock().await;
+        guarded.keys.remove(&(namespace, *public_key));
+
+        if let Some(directory) = self.directory.as_ref() {
+            let _ = fs::remove_file(directory.join(file_name(namespace, public_key)));
+        }
+    }
+
+    /// Atomically replaces `old_public_key` with a freshly-generated key of the given
+    /// `algorithm`, both under `namespace`.
+    ///
+    /// The new key is generated and inserted, and the old key is removed, while holding the
+    /// keystore's lock for the whole operation, so that no window exists during which either
+    /// zero or two keys are active for `namespace`. This is meant for session key rotation.
+    ///
+    /// Returns the public key of the newly-generated key. If `save` contains a passphrase, that
+    /// key is additionally written to disk, encrypted with that passphrase; this requires the
+    /// keystore to have been created with [`Keystore::with_directory`].
+    pub async fn rotate(
+        &self,
+        namespace: KeyNamespace,
+        old_public_key: &PublicKey,
+        algorithm: KeyAlgorithm,
+        save: Option<&str>,
+    ) -> Result<PublicKey, KeystoreSaveError> {
+        let mut guarded = self.guarded.lock().await;
+
+        let (new_public_key, new_private_key, new_private_key_bytes) = match algorithm {
+            KeyAlgorithm::Ed25519 => {
+                let private_key = ed25519_zebra::SigningKey::new(&mut guarded.gen_rng);
+                let public_key: [u8; 32] =
+                    ed25519_zebra::VerificationKey::from(&private_key).into();
+                let private_key_bytes = private_key.as_ref().to_vec();
+                (
+                    PublicKey::Ed25519(public_key),
+                    PrivateKey::MemoryEd25519(private_key),
+                    private_key_bytes,
+                )
+            }
+            KeyAlgorithm::Sr25519 => {
+                let keypair = schnorrkel::Keypair::generate_with(&mut guarded.gen_rng);
+                let public_key = keypair.public.to_bytes();
+                let private_key_bytes = keypair.secret.to_bytes().to_vec();
+                (
+                    PublicKey::Sr25519(public_key),
+                    PrivateKey::MemorySr25519(keypair),
+                    private_key_bytes,
+                )
+            }
+            KeyAlgorithm::Ecdsa => {
+                let mut private_key_bytes = [0u8; 32];
+                guarded.gen_rng.fill(&mut private_key_bytes);
+                let signing_key = k256::ecdsa::SigningKey::from_bytes(&private_key_bytes[..])
+                    .expect(
+                        "rejection sampling on a 32-byte secp256k1 scalar practically never fails",
+                    );
+                let public_key = compressed_ecdsa_public_key(&signing_key);
+                (
+                    PublicKey::Ecdsa(public_key),
+                    PrivateKey::MemoryEcdsa(signing_key),
+                    private_key_bytes.to_vec(),
+                )
+            }
+        };
+
+        if let Some(passphrase) = save {
+            let directory = self
+                .directory
+                .as_ref()
+                .ok_or(KeystoreSaveError::NoDirectory)?;
+            save_key_file(
+                directory,
+                namespace,
+                &new_public_key,
+                &new_private_key_bytes,
+                passphrase,
+                &mut guarded.gen_rng,
+            )?;
+        }
+
+        guarded.keys.insert((namespace, new_public_key), new_private_key);
+        guarded.keys.remove(&(namespace, *old_public_key));
+
+        if let Some(directory) = self.directory.as_ref() {
+            let _ = fs::remove_file(directory.join(file_name(namespace, old_public_key)));
+        }
+
+        Ok(new_public_key)
+    }
 }
 
 struct Guarded {
     gen_rng: rand_chacha::ChaCha20Rng,
-    keys: hashbrown::HashMap<(KeyNamespace, [u8; 32]), PrivateKey, SipHasherBuild>,
+    keys: hashbrown::HashMap<(KeyNamespace, PublicKey), PrivateKey, SipHasherBuild>,
+}
+
+impl Guarded {
+    fn empty(randomness_seed: [u8; 32]) -> Self {
+        let mut gen_rng = rand_chacha::ChaCha20Rng::from_seed(randomness_seed);
+
+        let keys = hashbrown::HashMap::with_capacity_and_hasher(32, {
+            SipHasherBuild::new(gen_rng.sample(rand::distributions::Standard))
+        });
+
+        Guarded { gen_rng, keys }
+    }
 }
 
 pub struct VrfSignature {
+    /// VRF pre-output, i.e. the actual value of the VRF, as needed by consensus code (e.g. BABE)
+    /// to determine slot authorship.
+    pub output: [u8; 32],
     pub proof: [u8; 64],
 }
 
+#[derive(Debug, derive_more::Display, Clone)]
+pub enum VerifyVrfError {
+    InvalidPublicKey,
+    InvalidOutput,
+    InvalidProof,
+    #[display(fmt = "VRF proof doesn't match the given output and transcript")]
+    VerificationFailed,
+}
+
+/// Error potentially returned by [`Keystore::export_json`].
+#[derive(Debug, derive_more::Display)]
+pub enum ExportJsonError {
+    #[display(fmt = "{}", _0)]
+    Sign(SignError),
+    /// The JSON keystore format has no variant for Ecdsa keys.
+    UnsupportedAlgorithm,
+}
+
+/// Error potentially returned by [`Keystore::import_json`].
+#[derive(Debug, derive_more::Display)]
+pub enum ImportJsonError {
+    /// The document isn't valid JSON, doesn't have the expected shape, or decrypts to a value
+    /// that doesn't have the expected length or structure.
+    InvalidJson,
+    /// The document declares an encoding that this module doesn't know how to decode.
+    UnsupportedEncoding,
+    /// The document declares a key algorithm that this module doesn't know how to decode.
+    UnsupportedAlgorithm,
+    /// Failed to decrypt the document. Most likely indicates that the passphrase is wrong.
+    #[display(fmt = "Failed to decrypt key file, check that the passphrase is correct")]
+    Decrypt,
+}
+
+/// On-disk JSON structure used by [`Keystore::export_json`] and [`Keystore::import_json`],
+/// matching the format used by Substrate's keystore and the Polkadot.js extension.
+#[derive(Serialize, Deserialize)]
+struct JsonKeyFile {
+    encoded: String,
+    encoding: JsonKeyEncoding,
+    address: String,
+    #[serde(default)]
+    meta: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonKeyEncoding {
+    /// `["pkcs8", "<ed25519|sr25519>"]`.
+    content: (String, String),
+    /// `["scrypt", "xsalsa20-poly1305"]`.
+    #[serde(rename = "type")]
+    ty: Vec<String>,
+    version: String,
+}
+
+/// DER header preceding the 32-byte secret seed in the PKCS8 encoding of an Ed25519 private key,
+/// as produced and expected by `sp-core` and the Polkadot.js "json" keystore format.
+const ED25519_PKCS8_HEADER: [u8; 16] = [48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32];
+/// DER divider between the secret seed and the public key in that same encoding.
+const ED25519_PKCS8_DIVIDER: [u8; 5] = [161, 35, 3, 33, 0];
+
+/// Builds the PKCS8 encoding of an Ed25519 keypair used by the JSON keystore format.
+fn pkcs8_encode_ed25519(seed: &[u8; 32], public_key: &[u8; 32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(
+        ED25519_PKCS8_HEADER.len() + 32 + ED25519_PKCS8_DIVIDER.len() + 32,
+    );
+    out.extend_from_slice(&ED25519_PKCS8_HEADER);
+    out.extend_from_slice(seed);
+    out.extend_from_slice(&ED25519_PKCS8_DIVIDER);
+    out.extend_from_slice(public_key);
+    out
+}
+
+/// Inverse of [`pkcs8_encode_ed25519`].
+fn pkcs8_decode_ed25519(bytes: &[u8]) -> Option<([u8; 32], [u8; 32])> {
+    let expected_len = ED25519_PKCS8_HEADER.len() + 32 + ED25519_PKCS8_DIVIDER.len() + 32;
+    if bytes.len() != expected_len {
+        return None;
+    }
+
+    let header_end = ED25519_PKCS8_HEADER.len();
+    let seed_end = header_end + 32;
+    let divider_end = seed_end + ED25519_PKCS8_DIVIDER.len();
+
+    if bytes[..header_end] != ED25519_PKCS8_HEADER {
+        return None;
+    }
+    if bytes[seed_end..divider_end] != ED25519_PKCS8_DIVIDER {
+        return None;
+    }
+
+    let seed = bytes[header_end..seed_end].try_into().unwrap();
+    let public_key = bytes[divider_end..].try_into().unwrap();
+    Some((seed, public_key))
+}
+
+/// Signature produced by [`Keystore::sign`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Signature {
+    Ed25519([u8; 64]),
+    Sr25519([u8; 64]),
+    /// 64-byte recoverable ECDSA signature followed by the recovery identifier.
+    Ecdsa([u8; 65]),
+}
+
 #[derive(Debug, derive_more::Display, Clone)]
 pub enum SignError {
     UnknownPublicKey,
@@ -258,8 +1082,664 @@ pub enum SignVrfError {
     WrongKeyAlgorithm,
 }
 
+/// One junction of a SURI-style (`//hard` and `/soft`) hierarchical derivation path, as accepted
+/// by [`Keystore::derive`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeriveJunction {
+    /// A `//junction`. Supported by both Ed25519 and Sr25519.
+    Hard([u8; 32]),
+    /// A `/junction`. Only supported by Sr25519; its public key can be derived from the parent's
+    /// public key alone, without knowledge of the parent's private key.
+    Soft([u8; 32]),
+}
+
+impl DeriveJunction {
+    /// Builds a hard junction from a junction identifier.
+    pub fn hard(id: &[u8]) -> Self {
+        DeriveJunction::Hard(junction_chain_code(id))
+    }
+
+    /// Builds a soft junction from a junction identifier.
+    pub fn soft(id: &[u8]) -> Self {
+        DeriveJunction::Soft(junction_chain_code(id))
+    }
+}
+
+/// Turns a junction identifier into a 32-byte chain code, like Substrate's `DeriveJunction` does:
+/// short identifiers are padded with zeroes, long ones are hashed with Blake2-256.
+fn junction_chain_code(id: &[u8]) -> [u8; 32] {
+    let mut chain_code = [0; 32];
+    if id.len() <= 32 {
+        chain_code[..id.len()].copy_from_slice(id);
+    } else {
+        chain_code.copy_from_slice(&Blake2b256::digest(id));
+    }
+    chain_code
+}
+
+/// Error potentially returned by [`Keystore::derive`].
+#[derive(Debug, derive_more::Display)]
+pub enum DeriveError {
+    #[display(fmt = "{}", _0)]
+    Sign(SignError),
+    /// Soft junctions were requested for an Ed25519 key, which only supports hard junctions.
+    SoftJunctionUnsupported,
+    /// Derivation was requested for an Ecdsa key, which doesn't support derivation.
+    UnsupportedAlgorithm,
+    #[display(fmt = "{}", _0)]
+    Save(KeystoreSaveError),
+}
+
+/// Error potentially returned by [`Keystore::with_directory`].
+#[derive(Debug, derive_more::Display)]
+pub enum KeystoreLoadError {
+    /// Error while accessing the filesystem.
+    Io(io::Error),
+    /// A key file is too short to be valid, has an unknown algorithm tag, has decrypted content
+    /// whose length doesn't match the algorithm its tag indicates, or holds a private key whose
+    /// public key doesn't match the one in its file name.
+    InvalidKeyFile,
+    /// Failed to decrypt a key file. Most likely indicates that the passphrase is wrong, but
+    /// could also indicate that the file is corrupted.
+    #[display(fmt = "Failed to decrypt key file, check that the passphrase is correct")]
+    Decrypt,
+}
+
+/// Error potentially returned when generating, inserting, or deriving a key with a request to
+/// save it to disk.
+#[derive(Debug, derive_more::Display)]
+pub enum KeystoreSaveError {
+    /// The keystore wasn't created with [`Keystore::with_directory`], and thus has no directory
+    /// to save keys to.
+    NoDirectory,
+    /// Error while accessing the filesystem.
+    Io(io::Error),
+}
+
+impl From<io::Error> for KeystoreSaveError {
+    fn from(err: io::Error) -> Self {
+        KeystoreSaveError::Io(err)
+    }
+}
+
 enum PrivateKey {
     MemoryEd25519(ed25519_zebra::SigningKey),
     MemorySr25519(schnorrkel::Keypair),
-    // TODO: File(path::PathBuf),
+    MemoryEcdsa(k256::ecdsa::SigningKey),
+}
+
+/// Returns the 33-byte SEC1-compressed public key corresponding to `signing_key`.
+fn compressed_ecdsa_public_key(signing_key: &k256::ecdsa::SigningKey) -> [u8; 33] {
+    let encoded_point = signing_key.verifying_key().to_encoded_point(true);
+    let mut public_key = [0; 33];
+    public_key.copy_from_slice(encoded_point.as_bytes());
+    public_key
+}
+
+/// Blake2b with a 32-byte output, as used throughout Substrate's crypto primitives.
+type Blake2b256 = blake2::Blake2b<blake2::digest::consts::U32>;
+
+/// Hard-junction derivation for Ed25519, as implemented by `sp-core`'s `ed25519::Pair::derive`:
+/// the next seed is `blake2_256("Ed25519HDKD" || secret_seed || chain_code)`.
+fn derive_ed25519_hard(secret_seed: &[u8; 32], chain_code: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Blake2b256::new();
+    hasher.update(b"Ed25519HDKD");
+    hasher.update(secret_seed);
+    hasher.update(chain_code);
+    hasher.finalize().into()
+}
+
+/// Soft-junction derivation for Sr25519, delegating to schnorrkel's own key derivation: the
+/// chain code is appended to a Merlin transcript and used to add a derived scalar to the secret
+/// key, leaving the corresponding public key derivable from the parent's public key alone.
+fn derive_sr25519_soft(
+    secret: &schnorrkel::SecretKey,
+    chain_code: &[u8; 32],
+) -> schnorrkel::SecretKey {
+    secret
+        .derived_key_simple(schnorrkel::derive::ChainCode(*chain_code), [])
+        .0
+}
+
+/// Hard-junction derivation for Sr25519: the current secret key and the chain code are hashed
+/// into a fresh 32-byte mini-secret, which is then expanded back into a full keypair, the same
+/// way `sp-core`'s `sr25519::Pair::derive` does.
+fn derive_sr25519_hard(
+    secret: &schnorrkel::SecretKey,
+    chain_code: &[u8; 32],
+) -> schnorrkel::SecretKey {
+    let (mini_secret, _) =
+        secret.hard_derive_mini_secret_key(Some(schnorrkel::derive::ChainCode(*chain_code)), b"");
+    mini_secret.expand(schnorrkel::ExpansionMode::Ed25519)
+}
+
+/// Signature algorithm of a key. Also used as the tag stored as the first byte of an on-disk
+/// key file, identifying how to interpret the decrypted bytes that follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Ed25519 = 0,
+    Sr25519 = 1,
+    Ecdsa = 2,
+}
+
+impl KeyAlgorithm {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(KeyAlgorithm::Ed25519),
+            1 => Some(KeyAlgorithm::Sr25519),
+            2 => Some(KeyAlgorithm::Ecdsa),
+            _ => None,
+        }
+    }
+}
+
+/// Parameters of the scrypt key derivation function used to turn a user-provided passphrase
+/// into the key used to encrypt/decrypt key files.
+const SCRYPT_LOG_N: u8 = 15; // N = 2^15
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24; // XSalsa20Poly1305 nonce size.
+
+/// Derives a 32-byte symmetric encryption key from `passphrase` and `salt` using scrypt.
+fn derive_encryption_key(passphrase: &str, salt: &[u8; SALT_LEN]) -> [u8; 32] {
+    let params = scrypt::Params::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P, 32)
+        .unwrap_or_else(|_| unreachable!());
+    let mut output = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut output)
+        .unwrap_or_else(|_| unreachable!());
+    output
+}
+
+/// Encrypts `private_key_bytes` with a key derived from `passphrase`, and writes the result to
+/// `directory` under the name derived from `namespace` and `public_key`.
+fn save_key_file(
+    directory: &path::Path,
+    namespace: KeyNamespace,
+    public_key: &PublicKey,
+    private_key_bytes: &[u8],
+    passphrase: &str,
+    rng: &mut rand_chacha::ChaCha20Rng,
+) -> Result<(), KeystoreSaveError> {
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill(&mut salt);
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce_bytes);
+
+    let encryption_key = derive_encryption_key(passphrase, &salt);
+    let cipher = XSalsa20Poly1305::new(aead::generic_array::GenericArray::from_slice(
+        &encryption_key,
+    ));
+    let ciphertext = cipher
+        .encrypt(
+            aead::generic_array::GenericArray::from_slice(&nonce_bytes),
+            private_key_bytes,
+        )
+        .unwrap_or_else(|_| unreachable!());
+
+    let mut file_contents = Vec::with_capacity(1 + SALT_LEN + NONCE_LEN + ciphertext.len());
+    file_contents.push(public_key.algorithm() as u8);
+    file_contents.extend_from_slice(&salt);
+    file_contents.extend_from_slice(&nonce_bytes);
+    file_contents.extend_from_slice(&ciphertext);
+
+    fs::create_dir_all(directory)?;
+    fs::write(directory.join(file_name(namespace, public_key)), file_contents)?;
+    Ok(())
+}
+
+/// Decrypts the content of a key file previously produced by [`save_key_file`].
+///
+/// `public_key_bytes` is the key part of the file name, as returned by [`decode_file_name`]; it
+/// is unauthenticated file-name metadata, so the public key is recomputed from the decrypted
+/// private key material and the file is rejected with [`KeystoreLoadError::InvalidKeyFile`] if
+/// the two disagree, the same way [`Keystore::import_json`] validates its own embedded public
+/// key against the decrypted seed.
+fn decrypt_key_file(
+    contents: &[u8],
+    public_key_bytes: &[u8],
+    passphrase: &str,
+) -> Result<(PublicKey, PrivateKey), KeystoreLoadError> {
+    if contents.len() < 1 + SALT_LEN + NONCE_LEN {
+        return Err(KeystoreLoadError::InvalidKeyFile);
+    }
+
+    let algorithm =
+        KeyAlgorithm::from_u8(contents[0]).ok_or(KeystoreLoadError::InvalidKeyFile)?;
+    let salt: [u8; SALT_LEN] = contents[1..1 + SALT_LEN].try_into().unwrap();
+    let nonce_bytes: [u8; NONCE_LEN] = contents[1 + SALT_LEN..1 + SALT_LEN + NONCE_LEN]
+        .try_into()
+        .unwrap();
+    let ciphertext = &contents[1 + SALT_LEN + NONCE_LEN..];
+
+    let encryption_key = derive_encryption_key(passphrase, &salt);
+    let cipher = XSalsa20Poly1305::new(aead::generic_array::GenericArray::from_slice(
+        &encryption_key,
+    ));
+    let private_key_bytes = cipher
+        .decrypt(
+            aead::generic_array::GenericArray::from_slice(&nonce_bytes),
+            ciphertext,
+        )
+        .map_err(|_| KeystoreLoadError::Decrypt)?;
+
+    Ok(match algorithm {
+        KeyAlgorithm::Ed25519 => {
+            let file_name_public_key: [u8; 32] = public_key_bytes
+                .try_into()
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let private_key_bytes: [u8; 32] = private_key_bytes
+                .try_into()
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let signing_key = ed25519_zebra::SigningKey::try_from(private_key_bytes)
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let public_key: [u8; 32] = ed25519_zebra::VerificationKey::from(&signing_key).into();
+            if public_key != file_name_public_key {
+                return Err(KeystoreLoadError::InvalidKeyFile);
+            }
+            (
+                PublicKey::Ed25519(public_key),
+                PrivateKey::MemoryEd25519(signing_key),
+            )
+        }
+        KeyAlgorithm::Sr25519 => {
+            let file_name_public_key: [u8; 32] = public_key_bytes
+                .try_into()
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let secret = schnorrkel::SecretKey::from_bytes(&private_key_bytes)
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let keypair = secret.to_keypair();
+            let public_key = keypair.public.to_bytes();
+            if public_key != file_name_public_key {
+                return Err(KeystoreLoadError::InvalidKeyFile);
+            }
+            (
+                PublicKey::Sr25519(public_key),
+                PrivateKey::MemorySr25519(keypair),
+            )
+        }
+        KeyAlgorithm::Ecdsa => {
+            let file_name_public_key: [u8; 33] = public_key_bytes
+                .try_into()
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let private_key_bytes: &[u8; 32] = (&private_key_bytes[..])
+                .try_into()
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let signing_key = k256::ecdsa::SigningKey::from_bytes(&private_key_bytes[..])
+                .map_err(|_| KeystoreLoadError::InvalidKeyFile)?;
+            let public_key = compressed_ecdsa_public_key(&signing_key);
+            if public_key != file_name_public_key {
+                return Err(KeystoreLoadError::InvalidKeyFile);
+            }
+            (
+                PublicKey::Ecdsa(public_key),
+                PrivateKey::MemoryEcdsa(signing_key),
+            )
+        }
+    })
+}
+
+/// Builds the name of the file a key is stored in: `hex(namespace || public_key)`.
+fn file_name(namespace: KeyNamespace, public_key: &PublicKey) -> String {
+    let mut bytes = Vec::with_capacity(4 + 33);
+    bytes.extend_from_slice(namespace.as_bytes());
+    bytes.extend_from_slice(public_key.as_bytes());
+    hex::encode(bytes)
+}
+
+/// Inverse of [`file_name`], except that the public key bytes are returned as-is, without an
+/// indication of which algorithm they belong to; that information is only available once the
+/// encrypted content of the file has been decrypted. Returns `None` if `file_name` doesn't match
+/// the expected format, which is assumed to mean that the file wasn't created by this module.
+fn decode_file_name(file_name: &str) -> Option<(KeyNamespace, Vec<u8>)> {
+    let decoded = hex::decode(file_name).ok()?;
+    if decoded.len() != 4 + 32 && decoded.len() != 4 + 33 {
+        return None;
+    }
+
+    let namespace = KeyNamespace::from_bytes(<&[u8; 4]>::try_from(&decoded[0..4]).unwrap())?;
+    Some((namespace, decoded[4..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_ed25519_hard_junction() {
+        let keystore = Keystore::new([1; 32]);
+        let base = futures::executor::block_on(keystore.generate_ed25519(KeyNamespace::Aura, None))
+            .unwrap();
+
+        let derived = futures::executor::block_on(keystore.derive(
+            KeyNamespace::Aura,
+            &PublicKey::Ed25519(base),
+            std::iter::once(DeriveJunction::hard(b"Alice")),
+            None,
+        ))
+        .unwrap();
+
+        // Deriving through the same path twice must yield the same child key.
+        let derived_again = futures::executor::block_on(keystore.derive(
+            KeyNamespace::Aura,
+            &PublicKey::Ed25519(base),
+            std::iter::once(DeriveJunction::hard(b"Alice")),
+            None,
+        ))
+        .unwrap();
+        assert_eq!(derived, derived_again);
+        assert_ne!(derived, PublicKey::Ed25519(base));
+
+        // A soft junction isn't supported for Ed25519.
+        let err = futures::executor::block_on(keystore.derive(
+            KeyNamespace::Aura,
+            &PublicKey::Ed25519(base),
+            std::iter::once(DeriveJunction::soft(b"Alice")),
+            None,
+        ))
+        .unwrap_err();
+        assert!(matches!(err, DeriveError::SoftJunctionUnsupported));
+    }
+
+    #[test]
+    fn derive_sr25519_hard_and_soft_junctions() {
+        let keystore = Keystore::new([2; 32]);
+        let base = futures::executor::block_on(keystore.generate_sr25519(KeyNamespace::Babe, None))
+            .unwrap();
+
+        let hard = futures::executor::block_on(keystore.derive(
+            KeyNamespace::Babe,
+            &PublicKey::Sr25519(base),
+            std::iter::once(DeriveJunction::hard(b"Alice")),
+            None,
+        ))
+        .unwrap();
+        let soft = futures::executor::block_on(keystore.derive(
+            KeyNamespace::Babe,
+            &PublicKey::Sr25519(base),
+            std::iter::once(DeriveJunction::soft(b"Alice")),
+            None,
+        ))
+        .unwrap();
+
+        assert_ne!(hard, PublicKey::Sr25519(base));
+        assert_ne!(soft, PublicKey::Sr25519(base));
+        assert_ne!(hard, soft);
+    }
+
+    #[test]
+    fn vrf_sign_and_verify_round_trip() {
+        let keystore = Keystore::new([3; 32]);
+        let public_key =
+            futures::executor::block_on(keystore.generate_sr25519(KeyNamespace::Babe, None))
+                .unwrap();
+
+        let transcript_items = || std::iter::once((&b"slot"[..], either::Right(42u64)));
+
+        let signature = futures::executor::block_on(keystore.sign_sr25519_vrf(
+            KeyNamespace::Babe,
+            &public_key,
+            b"babe_vrf",
+            transcript_items(),
+        ))
+        .unwrap();
+
+        Keystore::verify_sr25519_vrf(
+            &public_key,
+            b"babe_vrf",
+            transcript_items(),
+            &signature.output,
+            &signature.proof,
+        )
+        .unwrap();
+
+        // A mismatching transcript must be rejected.
+        let other_transcript = || std::iter::once((&b"slot"[..], either::Right(43u64)));
+        let err = Keystore::verify_sr25519_vrf(
+            &public_key,
+            b"babe_vrf",
+            other_transcript(),
+            &signature.output,
+            &signature.proof,
+        )
+        .unwrap_err();
+        assert!(matches!(err, VerifyVrfError::VerificationFailed));
+    }
+
+    #[test]
+    fn json_export_import_round_trip_sr25519() {
+        let keystore = Keystore::new([4; 32]);
+        let public_key =
+            futures::executor::block_on(keystore.generate_sr25519(KeyNamespace::Aura, None))
+                .unwrap();
+
+        let json = futures::executor::block_on(keystore.export_json(
+            KeyNamespace::Aura,
+            &PublicKey::Sr25519(public_key),
+            "correct horse battery staple",
+        ))
+        .unwrap();
+
+        let other_keystore = Keystore::new([5; 32]);
+        let imported_public_key = futures::executor::block_on(other_keystore.import_json(
+            std::iter::once(KeyNamespace::Aura),
+            &json,
+            "correct horse battery staple",
+        ))
+        .unwrap();
+
+        assert_eq!(imported_public_key, PublicKey::Sr25519(public_key));
+        assert!(futures::executor::block_on(
+            other_keystore.has_key(KeyNamespace::Aura, &imported_public_key)
+        ));
+
+        // A wrong passphrase must fail to decrypt rather than silently produce garbage.
+        let err = futures::executor::block_on(other_keystore.import_json(
+            std::iter::once(KeyNamespace::Aura),
+            &json,
+            "wrong passphrase",
+        ))
+        .unwrap_err();
+        assert!(matches!(err, ImportJsonError::Decrypt));
+    }
+
+    #[test]
+    fn json_export_import_round_trip_ed25519() {
+        let keystore = Keystore::new([6; 32]);
+        let public_key =
+            futures::executor::block_on(keystore.generate_ed25519(KeyNamespace::Grandpa, None))
+                .unwrap();
+
+        let json = futures::executor::block_on(keystore.export_json(
+            KeyNamespace::Grandpa,
+            &PublicKey::Ed25519(public_key),
+            "passphrase",
+        ))
+        .unwrap();
+
+        let other_keystore = Keystore::new([7; 32]);
+        let imported_public_key = futures::executor::block_on(other_keystore.import_json(
+            std::iter::once(KeyNamespace::Grandpa),
+            &json,
+            "passphrase",
+        ))
+        .unwrap();
+
+        assert_eq!(imported_public_key, PublicKey::Ed25519(public_key));
+    }
+
+    /// Returns a fresh, empty temporary directory for a [`Keystore::with_directory`] test, named
+    /// after `name` to avoid collisions between tests running concurrently in the same process.
+    fn temp_keystore_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("smoldot-keystore-test-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn with_directory_persists_and_reloads_keys() {
+        let dir = temp_keystore_dir("with-directory-persists-and-reloads-keys");
+
+        let public_key = {
+            let keystore = Keystore::with_directory([20; 32], dir.clone(), "correct horse").unwrap();
+            futures::executor::block_on(
+                keystore.generate_ed25519(KeyNamespace::Aura, Some("correct horse")),
+            )
+            .unwrap()
+        };
+        let public_key = PublicKey::Ed25519(public_key);
+
+        // A fresh keystore pointed at the same directory must load the persisted key back, and
+        // it must still be usable for signing.
+        let reloaded = Keystore::with_directory([21; 32], dir.clone(), "correct horse").unwrap();
+        assert!(futures::executor::block_on(
+            reloaded.has_key(KeyNamespace::Aura, &public_key)
+        ));
+        futures::executor::block_on(reloaded.sign(KeyNamespace::Aura, &public_key, b"payload"))
+            .unwrap();
+
+        // The wrong passphrase must fail to decrypt rather than silently loading garbage.
+        let err = Keystore::with_directory([22; 32], dir.clone(), "wrong passphrase").unwrap_err();
+        assert!(matches!(err, KeystoreLoadError::Decrypt));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ecdsa_generate_and_sign_round_trip() {
+        let keystore = Keystore::new([8; 32]);
+        let public_key = futures::executor::block_on(
+            keystore.generate_ecdsa(KeyNamespace::AuthorityDiscovery, None),
+        )
+        .unwrap();
+
+        let payload = b"hello ecdsa";
+        let signature = futures::executor::block_on(keystore.sign(
+            KeyNamespace::AuthorityDiscovery,
+            &PublicKey::Ecdsa(public_key),
+            payload,
+        ))
+        .unwrap();
+
+        let bytes = match signature {
+            Signature::Ecdsa(bytes) => bytes,
+            _ => panic!("expected an Ecdsa signature"),
+        };
+
+        // Recover the public key from the recoverable signature and check it matches the one
+        // returned by `generate_ecdsa`, the same way a BEEFY/bridge verifier would.
+        let hashed_payload = Blake2b256::digest(payload);
+        let recovery_id = k256::ecdsa::RecoveryId::from_byte(bytes[64]).unwrap();
+        let signature = k256::ecdsa::Signature::from_slice(&bytes[..64]).unwrap();
+        let recovered = k256::ecdsa::VerifyingKey::recover_from_prehash(
+            &hashed_payload,
+            &signature,
+            recovery_id,
+        )
+        .unwrap();
+        let mut recovered_bytes = [0u8; 33];
+        recovered_bytes.copy_from_slice(recovered.to_encoded_point(true).as_bytes());
+        assert_eq!(recovered_bytes, public_key);
+    }
+
+    #[test]
+    fn ecdsa_insert_memory_is_deterministic() {
+        let mut keystore = Keystore::new([9; 32]);
+        let private_key = [42u8; 32];
+
+        let public_key = keystore
+            .insert_ecdsa_memory(
+                std::iter::once(KeyNamespace::AuthorityDiscovery),
+                &private_key,
+                None,
+            )
+            .unwrap();
+        assert!(futures::executor::block_on(keystore.has_key(
+            KeyNamespace::AuthorityDiscovery,
+            &PublicKey::Ecdsa(public_key)
+        )));
+
+        // Inserting the same private key again must yield the same public key.
+        let public_key_again = keystore
+            .insert_ecdsa_memory(
+                std::iter::once(KeyNamespace::AuthorityDiscovery),
+                &private_key,
+                None,
+            )
+            .unwrap();
+        assert_eq!(public_key, public_key_again);
+    }
+
+    #[test]
+    fn remove_deletes_the_key() {
+        let keystore = Keystore::new([10; 32]);
+        let public_key =
+            futures::executor::block_on(keystore.generate_sr25519(KeyNamespace::Grandpa, None))
+                .unwrap();
+        let public_key = PublicKey::Sr25519(public_key);
+
+        assert!(futures::executor::block_on(
+            keystore.has_key(KeyNamespace::Grandpa, &public_key)
+        ));
+
+        futures::executor::block_on(keystore.remove(KeyNamespace::Grandpa, &public_key));
+
+        assert!(!futures::executor::block_on(
+            keystore.has_key(KeyNamespace::Grandpa, &public_key)
+        ));
+    }
+
+    #[test]
+    fn remove_deletes_the_on_disk_file() {
+        let dir = temp_keystore_dir("remove-deletes-the-on-disk-file");
+        let keystore = Keystore::with_directory([23; 32], dir.clone(), "passphrase").unwrap();
+        let public_key = futures::executor::block_on(
+            keystore.generate_sr25519(KeyNamespace::Grandpa, Some("passphrase")),
+        )
+        .unwrap();
+        let public_key = PublicKey::Sr25519(public_key);
+
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        futures::executor::block_on(keystore.remove(KeyNamespace::Grandpa, &public_key));
+
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_replaces_the_key_atomically() {
+        let keystore = Keystore::new([11; 32]);
+        let old_public_key =
+            futures::executor::block_on(keystore.generate_sr25519(KeyNamespace::ImOnline, None))
+                .unwrap();
+        let old_public_key = PublicKey::Sr25519(old_public_key);
+
+        let new_public_key = futures::executor::block_on(keystore.rotate(
+            KeyNamespace::ImOnline,
+            &old_public_key,
+            KeyAlgorithm::Sr25519,
+            None,
+        ))
+        .unwrap();
+
+        assert_ne!(new_public_key, old_public_key);
+        assert!(!futures::executor::block_on(
+            keystore.has_key(KeyNamespace::ImOnline, &old_public_key)
+        ));
+        assert!(futures::executor::block_on(
+            keystore.has_key(KeyNamespace::ImOnline, &new_public_key)
+        ));
+
+        // The newly-rotated-in key must actually be usable for signing.
+        futures::executor::block_on(keystore.sign(
+            KeyNamespace::ImOnline,
+            &new_public_key,
+            b"payload",
+        ))
+        .unwrap();
+    }
 }